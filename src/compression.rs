@@ -0,0 +1,205 @@
+//! Content-negotiated compression of rendered badge SVGs.
+//!
+//! The compressed bytes for a given rendered body are always the same, so
+//! once we've compressed one we keep the bytes around instead of paying for
+//! deflate/brotli again the next time the same bytes come through — which
+//! happens whenever two requests render the same `(key, count, color)`
+//! tuple. Note that the view counter increments on every hit by default, so
+//! in practice this cache only pays off once `--dedup-window` (see
+//! `dedup.rs`) is turned on, or across distinct keys that happen to render
+//! identical bodies.
+
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+use lru::LruCache;
+
+#[derive(Debug)]
+pub enum Error {
+    Lock,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Lock => write!(f, "failed to acquire compressed body cache lock"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The compression scheme negotiated with a client, in preference order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn header_value(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// Picks the best encoding a client advertised via `Accept-Encoding`,
+    /// honoring q-values (an encoding with `q=0` is explicitly declined, and
+    /// higher q wins over lower q).
+    pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+        let mut best: Option<(Encoding, f32)> = None;
+
+        for token in accept_encoding.split(',') {
+            let mut parts = token.split(';');
+            let name = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+            let encoding = match name.as_str() {
+                "br" => Encoding::Brotli,
+                "gzip" => Encoding::Gzip,
+                _ => continue,
+            };
+
+            let q = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if q <= 0.0 {
+                continue;
+            }
+
+            let better = match best {
+                Some((_, best_q)) => {
+                    q > best_q || (q == best_q && encoding == Encoding::Brotli)
+                }
+                None => true,
+            };
+            if better {
+                best = Some((encoding, q));
+            }
+        }
+
+        best.map(|(encoding, _)| encoding)
+    }
+}
+
+fn compress(body: &str, encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Brotli => {
+            let mut out = Vec::new();
+            let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 5, 22);
+            writer
+                .write_all(body.as_bytes())
+                .expect("writing to an in-memory buffer cannot fail");
+            drop(writer);
+            out
+        }
+        Encoding::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder
+                .write_all(body.as_bytes())
+                .expect("writing to an in-memory buffer cannot fail");
+            encoder
+                .finish()
+                .expect("writing to an in-memory buffer cannot fail")
+        }
+    }
+}
+
+/// The compressed bytes, and whether they came from cache or were just
+/// computed. Callers use this to log cache outcomes.
+pub enum CacheOutcome {
+    Hit(Vec<u8>),
+    Miss(Vec<u8>),
+}
+
+impl CacheOutcome {
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            CacheOutcome::Hit(bytes) => bytes,
+            CacheOutcome::Miss(bytes) => bytes,
+        }
+    }
+
+    pub fn was_hit(&self) -> bool {
+        matches!(self, CacheOutcome::Hit(_))
+    }
+}
+
+/// The cache is keyed on the rendered body itself (plus encoding), not on
+/// `(key, count, color)`: the compressed bytes only depend on the bytes
+/// going in, and two different badges can render byte-identical bodies.
+type CacheKey = (String, Encoding);
+
+/// Bounded cache of already-compressed badge bodies, keyed on the rendered
+/// body's bytes and the negotiated encoding.
+pub struct CompressedBodyCache {
+    entries: Mutex<LruCache<CacheKey, Vec<u8>>>,
+}
+
+impl CompressedBodyCache {
+    pub fn new(capacity: usize) -> CompressedBodyCache {
+        CompressedBodyCache {
+            entries: Mutex::new(LruCache::new(
+                NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::new(1).unwrap()),
+            )),
+        }
+    }
+
+    /// Returns the compressed bytes for `body`, compressing and caching them
+    /// on the first request for this body/encoding pair.
+    pub fn get_or_compress(&self, body: &str, encoding: Encoding) -> Result<CacheOutcome, Error> {
+        let cache_key = (body.to_string(), encoding);
+
+        let mut cache = self.entries.lock().map_err(|_| Error::Lock)?;
+        if let Some(cached) = cache.get(&cache_key) {
+            return Ok(CacheOutcome::Hit(cached.clone()));
+        }
+
+        let compressed = compress(body, encoding);
+        cache.put(cache_key, compressed.clone());
+        Ok(CacheOutcome::Miss(compressed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_prefers_brotli_when_both_accepted() {
+        assert_eq!(Encoding::negotiate("br, gzip"), Some(Encoding::Brotli));
+    }
+
+    #[test]
+    fn negotiate_falls_back_to_gzip_alone() {
+        assert_eq!(Encoding::negotiate("gzip"), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn negotiate_returns_none_for_unsupported_encodings() {
+        assert_eq!(Encoding::negotiate("identity, deflate"), None);
+    }
+
+    #[test]
+    fn negotiate_skips_an_encoding_explicitly_declined_with_q_zero() {
+        assert_eq!(Encoding::negotiate("br;q=0, gzip"), Some(Encoding::Gzip));
+    }
+
+    #[test]
+    fn negotiate_picks_the_higher_q_value() {
+        assert_eq!(
+            Encoding::negotiate("br;q=0.2, gzip;q=0.8"),
+            Some(Encoding::Gzip)
+        );
+    }
+
+    #[test]
+    fn negotiate_treats_an_unparseable_q_as_the_default_of_one() {
+        assert_eq!(
+            Encoding::negotiate("br;q=not-a-number"),
+            Some(Encoding::Brotli)
+        );
+    }
+}
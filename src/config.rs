@@ -0,0 +1,86 @@
+//! Layered configuration: a `--config <path>` TOML file merged with CLI
+//! flags, with explicit flags always winning over file values.
+
+use std::{collections::HashMap, net, path::Path, time::Duration};
+
+use serde::Deserialize;
+use tokio::{fs::File, io::AsyncReadExt};
+
+use crate::FillMode;
+
+/// Errors produced while resolving a fully layered configuration.
+#[derive(Debug)]
+pub enum Error {
+    InvalidHost(net::AddrParseError),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidHost(why) => write!(f, "invalid `host` value: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// One named template + color scale pair, selectable at request time via the
+/// `theme` query parameter.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ThemeFile {
+    pub template_path: String,
+    pub colors_path: String,
+}
+
+/// The shape of the `--config` TOML file. Every field is optional so a file
+/// only needs to set what it wants to override.
+#[derive(Debug, Default, Deserialize)]
+pub struct FileConfig {
+    pub port: Option<u16>,
+    pub host: Option<String>,
+    pub max_views: Option<u64>,
+    pub template_path: Option<String>,
+    pub colors_path: Option<String>,
+    pub marker_pattern: Option<String>,
+    pub default_fill_mode: Option<FillMode>,
+    #[serde(default)]
+    pub themes: HashMap<String, ThemeFile>,
+}
+
+impl FileConfig {
+    pub async fn from_file<P>(path: P) -> std::io::Result<FileConfig>
+    where
+        P: AsRef<Path>,
+    {
+        let mut file = File::open(path).await?;
+        let mut buffer = String::new();
+        file.read_to_string(&mut buffer).await?;
+
+        toml::from_str(&buffer)
+            .map_err(|why| std::io::Error::new(std::io::ErrorKind::InvalidData, why))
+    }
+}
+
+/// The name of the theme used when a request doesn't pick one.
+pub const DEFAULT_THEME: &str = "default";
+
+/// A fully resolved configuration: CLI flags layered over an optional config
+/// file, with sensible defaults filled in for anything still unset.
+#[derive(Debug)]
+pub struct ResolvedConfig {
+    pub host: net::IpAddr,
+    pub port: u16,
+    pub max_views: u64,
+    pub marker_pattern: String,
+    pub default_fill_mode: FillMode,
+    pub database_url: Option<String>,
+    pub dedup_window: Duration,
+    pub forwarded_for_header: String,
+    pub themes: HashMap<String, ThemeFile>,
+}
+
+impl ResolvedConfig {
+    pub fn address(&self) -> net::SocketAddr {
+        (self.host, self.port).into()
+    }
+}
@@ -0,0 +1,147 @@
+//! Per-visitor deduplication so refreshes from the same viewer don't
+//! inflate a badge's count.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Errors produced while reading or updating the deduplication map.
+#[derive(Debug)]
+pub enum Error {
+    Lock,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Lock => write!(f, "failed to acquire dedup map lock"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Hashes a visitor's identity (forwarded IP + user agent) down to a
+/// fingerprint we can key a seen-set on without storing PII.
+pub fn fingerprint(forwarded_for: Option<&str>, user_agent: Option<&str>) -> String {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    forwarded_for.unwrap_or("").hash(&mut hasher);
+    user_agent.unwrap_or("").hash(&mut hasher);
+    format!("{:x}", hasher.finish())
+}
+
+/// Tracks the last time each `(key, fingerprint)` pair was seen, so a repeat
+/// hit within `ttl` of the last one can be deduplicated. A `ttl` of zero
+/// disables deduplication entirely.
+pub struct Deduplicator {
+    ttl: Duration,
+    last_seen: Mutex<HashMap<String, HashMap<String, Instant>>>,
+}
+
+impl Deduplicator {
+    pub fn new(ttl: Duration) -> Deduplicator {
+        Deduplicator {
+            ttl,
+            last_seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.ttl.is_zero()
+    }
+
+    /// Returns `true` if this visitor already hit `key` within the TTL
+    /// window (and should therefore be deduplicated), recording the hit
+    /// either way.
+    pub fn should_dedup(&self, key: &str, fingerprint: &str, now: Instant) -> Result<bool, Error> {
+        if !self.is_enabled() {
+            return Ok(false);
+        }
+
+        let mut last_seen = self.last_seen.lock().map_err(|_| Error::Lock)?;
+        let fingerprints = last_seen.entry(key.to_string()).or_default();
+
+        let was_recently_seen = fingerprints
+            .get(fingerprint)
+            .is_some_and(|last_seen| now.duration_since(*last_seen) < self.ttl);
+
+        fingerprints.insert(fingerprint.to_string(), now);
+        Ok(was_recently_seen)
+    }
+
+    /// Drops fingerprints that are older than the TTL window. Meant to be
+    /// run periodically from a background task so the maps don't grow
+    /// without bound.
+    pub fn sweep(&self, now: Instant) -> Result<(), Error> {
+        let mut last_seen = self.last_seen.lock().map_err(|_| Error::Lock)?;
+        last_seen.retain(|_key, fingerprints| {
+            fingerprints.retain(|_fingerprint, seen_at| now.duration_since(*seen_at) < self.ttl);
+            !fingerprints.is_empty()
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_dedup_is_disabled_when_ttl_is_zero() {
+        let dedup = Deduplicator::new(Duration::ZERO);
+        let now = Instant::now();
+        assert!(!dedup.should_dedup("key", "fp", now).unwrap());
+        assert!(!dedup.should_dedup("key", "fp", now).unwrap());
+    }
+
+    #[test]
+    fn should_dedup_flags_a_repeat_hit_within_the_ttl() {
+        let dedup = Deduplicator::new(Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(!dedup.should_dedup("key", "fp", now).unwrap());
+        assert!(dedup
+            .should_dedup("key", "fp", now + Duration::from_secs(30))
+            .unwrap());
+    }
+
+    #[test]
+    fn should_dedup_allows_a_hit_again_once_the_ttl_elapses() {
+        let dedup = Deduplicator::new(Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(!dedup.should_dedup("key", "fp", now).unwrap());
+        assert!(!dedup
+            .should_dedup("key", "fp", now + Duration::from_secs(61))
+            .unwrap());
+    }
+
+    #[test]
+    fn should_dedup_tracks_fingerprints_independently_per_key() {
+        let dedup = Deduplicator::new(Duration::from_secs(60));
+        let now = Instant::now();
+        assert!(!dedup.should_dedup("key-a", "fp", now).unwrap());
+        assert!(!dedup.should_dedup("key-b", "fp", now).unwrap());
+    }
+
+    #[test]
+    fn sweep_drops_expired_fingerprints_but_keeps_fresh_ones() {
+        let dedup = Deduplicator::new(Duration::from_secs(60));
+        let now = Instant::now();
+        dedup.should_dedup("stale-key", "fp", now).unwrap();
+        dedup
+            .should_dedup("fresh-key", "fp", now + Duration::from_secs(50))
+            .unwrap();
+
+        dedup.sweep(now + Duration::from_secs(65)).unwrap();
+
+        assert!(!dedup.last_seen.lock().unwrap().contains_key("stale-key"));
+        assert!(!dedup
+            .should_dedup("stale-key", "fp", now + Duration::from_secs(65))
+            .unwrap());
+        assert!(dedup
+            .should_dedup("fresh-key", "fp", now + Duration::from_secs(70))
+            .unwrap());
+    }
+}
@@ -2,24 +2,34 @@
 // 1. Make a request to the server for a given string, and return a count back for visits
 //
 // TODO:
-// Persistence
 // 1=View, N=Views
 use argh::FromArgs;
 use http::{Response, StatusCode};
 use serde::Deserialize;
-use std::{
-    collections::HashMap,
-    fmt, net,
-    sync::{Arc, Mutex},
-};
+use std::{collections::HashMap, fmt, net, sync::Arc};
 use tokio::{
     fs::File,
     io::{self, AsyncReadExt},
 };
 use warp::Filter;
 
+mod compression;
+mod config;
+mod dedup;
+mod metrics;
+mod store;
+mod theme;
+
+use compression::{CompressedBodyCache, Encoding};
+use config::{FileConfig, ResolvedConfig, ThemeFile, DEFAULT_THEME};
+use dedup::Deduplicator;
+use metrics::Metrics;
+use store::CounterStore;
+use theme::{Theme, Themes};
+use tracing::Instrument;
+
 #[derive(Debug)]
-struct ViewCountSVG {
+pub(crate) struct ViewCountSVG {
     before_color_part: String,
     after_color_before_text_part: String,
     after_text_part: String,
@@ -56,18 +66,12 @@ impl ViewCountSVG {
         let mut read_iterator = buffer.split(pattern);
 
         Ok(ViewCountSVG {
-            before_color_part: read_iterator
-                .next()
-                .ok_or_else(|| Error::MissingPart)?
-                .to_string(),
+            before_color_part: read_iterator.next().ok_or(Error::MissingPart)?.to_string(),
             after_color_before_text_part: read_iterator
                 .next()
-                .ok_or_else(|| Error::MissingPart)?
-                .to_string(),
-            after_text_part: read_iterator
-                .next()
-                .ok_or_else(|| Error::MissingPart)?
+                .ok_or(Error::MissingPart)?
                 .to_string(),
+            after_text_part: read_iterator.next().ok_or(Error::MissingPart)?.to_string(),
         })
     }
 
@@ -78,18 +82,18 @@ impl ViewCountSVG {
                 + self.after_text_part.len(),
         );
 
-        buf.push_str(&*self.before_color_part);
+        buf.push_str(&self.before_color_part);
         buf.push_str(css_color_str);
-        buf.push_str(&*self.after_color_before_text_part);
+        buf.push_str(&self.after_color_before_text_part);
         buf.push_str(view_count);
-        buf.push_str(&*self.after_text_part);
+        buf.push_str(&self.after_text_part);
 
         buf
     }
 }
 
 #[derive(Debug)]
-struct ColorScale {
+pub(crate) struct ColorScale {
     colors: Vec<String>,
     max_views: u64,
 }
@@ -132,9 +136,9 @@ impl ColorScale {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Clone, Copy, Deserialize)]
 #[serde(rename_all = "lowercase")]
-enum FillMode {
+pub enum FillMode {
     Random,
     MIlestone,
 }
@@ -142,9 +146,9 @@ enum FillMode {
 #[derive(Deserialize)]
 struct QueryParameters {
     fill_mode: Option<FillMode>,
+    theme: Option<String>,
 }
 
-// TODO(haze): custom paths for colors and template
 #[derive(FromArgs)]
 /// profile_view_counter server
 struct Options {
@@ -159,23 +163,74 @@ struct Options {
     /// max number of views to count
     #[argh(option)]
     max_views: Option<u64>,
+
+    /// postgres connection string to persist view counts in, instead of
+    /// keeping them in memory
+    #[argh(option)]
+    database_url: Option<String>,
+
+    /// path to a TOML config file; explicit flags above still win over
+    /// values set in the file
+    #[argh(option)]
+    config: Option<String>,
+
+    /// how long, in seconds, a visitor's hit on a given key is remembered
+    /// and deduplicated; 0 (the default) disables deduplication
+    #[argh(option)]
+    dedup_window: Option<u64>,
+
+    /// the header to read a visitor's forwarded IP from, for deduplication;
+    /// defaults to "x-forwarded-for"
+    #[argh(option)]
+    forwarded_for_header: Option<String>,
+
+    /// tracing subscriber log level (e.g. "info", "debug"); defaults to "info"
+    #[argh(option)]
+    log_level: Option<String>,
 }
 
 impl Options {
-    fn max_views(&self) -> u64 {
-        self.max_views.unwrap_or(10_400)
-    }
-
-    fn address(&self) -> net::SocketAddr {
-        if self.host_on_all_interfaces {
-            ([0, 0, 0, 0], self.port()).into()
+    /// Merges this set of CLI flags with an optional config file, producing
+    /// the configuration the rest of `main` actually runs with.
+    fn resolve(&self, file: Option<FileConfig>) -> Result<ResolvedConfig, config::Error> {
+        let file = file.unwrap_or_default();
+
+        let host = if self.host_on_all_interfaces {
+            net::IpAddr::from([0, 0, 0, 0])
+        } else if let Some(host) = file.host.as_deref() {
+            host.parse().map_err(config::Error::InvalidHost)?
         } else {
-            ([127, 0, 0, 1], self.port()).into()
-        }
-    }
-
-    fn port(&self) -> u16 {
-        self.port.unwrap_or(3030)
+            net::IpAddr::from([127, 0, 0, 1])
+        };
+
+        let mut themes = file.themes;
+        themes
+            .entry(DEFAULT_THEME.to_string())
+            .or_insert(ThemeFile {
+                template_path: file
+                    .template_path
+                    .unwrap_or_else(|| "./view_count_template.svg".to_string()),
+                colors_path: file
+                    .colors_path
+                    .unwrap_or_else(|| "./colors.txt".to_string()),
+            });
+
+        Ok(ResolvedConfig {
+            host,
+            port: self.port.or(file.port).unwrap_or(3030),
+            max_views: self.max_views.or(file.max_views).unwrap_or(10_400),
+            marker_pattern: file
+                .marker_pattern
+                .unwrap_or_else(|| "$MARKER$".to_string()),
+            default_fill_mode: file.default_fill_mode.unwrap_or(FillMode::MIlestone),
+            database_url: self.database_url.clone(),
+            dedup_window: std::time::Duration::from_secs(self.dedup_window.unwrap_or(0)),
+            forwarded_for_header: self
+                .forwarded_for_header
+                .clone()
+                .unwrap_or_else(|| "x-forwarded-for".to_string()),
+            themes,
+        })
     }
 }
 
@@ -183,65 +238,303 @@ impl Options {
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let options: Options = argh::from_env();
 
-    let color_scale = Arc::new(ColorScale::from_file("./colors.txt", options.max_views()).await?);
-
-    let svg_pattern = "$MARKER$";
-    let svg_file =
-        Arc::new(ViewCountSVG::from_file("./view_count_template.svg", svg_pattern).await?);
+    let log_level = options
+        .log_level
+        .clone()
+        .unwrap_or_else(|| "info".to_string());
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::new(log_level))
+        .init();
+
+    let file_config = match &options.config {
+        Some(path) => Some(FileConfig::from_file(path).await?),
+        None => None,
+    };
+    let config = options.resolve(file_config)?;
+
+    let mut themes = HashMap::new();
+    for (name, theme_file) in &config.themes {
+        let color_scale =
+            Arc::new(ColorScale::from_file(&theme_file.colors_path, config.max_views).await?);
+        let svg_file = Arc::new(
+            ViewCountSVG::from_file(&theme_file.template_path, &config.marker_pattern).await?,
+        );
+        themes.insert(
+            name.clone(),
+            Theme {
+                svg: svg_file,
+                color_scale,
+            },
+        );
+    }
+    let themes = Arc::new(Themes::new(themes));
+
+    let counter_store = store::open(config.database_url.as_deref()).await?;
+    let compressed_body_cache = Arc::new(CompressedBodyCache::new(1024));
+    let default_fill_mode = config.default_fill_mode;
+    let metrics = Arc::new(Metrics::new());
+
+    let deduplicator = Arc::new(Deduplicator::new(config.dedup_window));
+    if deduplicator.is_enabled() {
+        let deduplicator = Arc::clone(&deduplicator);
+        let sweep_interval = config.dedup_window;
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(sweep_interval);
+            loop {
+                ticker.tick().await;
+                if let Err(why) = deduplicator.sweep(std::time::Instant::now()) {
+                    tracing::error!(error = %why, "failed to sweep dedup map");
+                }
+            }
+        });
+    }
 
-    let view_count_map: HashMap<String, u64> = HashMap::new();
-    let view_count_map = Arc::new(Mutex::new(view_count_map));
+    // warp's header filters need a `'static` header name, but the name is
+    // only known once we've parsed CLI flags/config at startup.
+    let forwarded_for_header: &'static str =
+        Box::leak(config.forwarded_for_header.clone().into_boxed_str());
 
-    let view_count_map_view = warp::any().map(move || Arc::clone(&view_count_map));
-    let svg_file_view = warp::any().map(move || Arc::clone(&svg_file));
-    let color_scale_view = warp::any().map(move || Arc::clone(&color_scale));
+    let counter_store_view = warp::any().map(move || Arc::clone(&counter_store));
+    let themes_view = warp::any().map(move || Arc::clone(&themes));
+    let compressed_body_cache_view = warp::any().map(move || Arc::clone(&compressed_body_cache));
+    let deduplicator_view = warp::any().map(move || Arc::clone(&deduplicator));
+    let metrics_view = warp::any().map(move || Arc::clone(&metrics));
 
     let index = warp::path::end().map(|| StatusCode::OK);
 
+    let metrics_route =
+        warp::path("metrics")
+            .and(metrics_view.clone())
+            .map(|metrics: Arc<Metrics>| match metrics.render() {
+                Ok(body) => Response::builder()
+                    .header("Content-Type", "text/plain; version=0.0.4")
+                    .status(StatusCode::OK)
+                    .body(body),
+                Err(why) => Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(format!("Failed to render metrics: {}", &why)),
+            });
+
     let view_count_route = warp::path::param::<String>()
-        .and(view_count_map_view)
-        .and(svg_file_view)
-        .and(color_scale_view)
+        .and(counter_store_view)
+        .and(themes_view)
+        .and(compressed_body_cache_view)
+        .and(deduplicator_view)
+        .and(metrics_view)
         .and(warp::filters::query::query::<QueryParameters>())
-        .map(
-            |input: String,
-             task_view_map: Arc<Mutex<HashMap<String, u64>>>,
-             svg_file: Arc<ViewCountSVG>,
-             color_scale: Arc<ColorScale>,
-             options: QueryParameters| match task_view_map.lock() {
-                Ok(mut gate) => {
-                    let view_count = gate.entry(input).or_insert(0);
-                    *view_count += 1;
-
-                    let color = match options.fill_mode.unwrap_or(FillMode::MIlestone) {
-                        FillMode::MIlestone => color_scale.hex_color_for_view_count(*view_count),
-                        FillMode::Random => color_scale.random_hex_color(),
-                    };
-
-                    let returned_svg_content =
-                        svg_file.template(&*format!("#{}", color), &*view_count.to_string());
-
-                    Response::builder()
-                        .header("Content-Type", "image/svg+xml; charset=utf-8")
-                        .header(
-                            "Cache-Control",
-                            "max-age=0, no-cache, no-store, must-revalidate",
-                        )
-                        .status(StatusCode::OK)
-                        .body(returned_svg_content)
+        .and(warp::header::optional::<String>("accept-encoding"))
+        .and(warp::header::optional::<String>(forwarded_for_header))
+        .and(warp::header::optional::<String>("user-agent"))
+        .and_then(
+            move |input: String,
+             counter_store: Arc<dyn CounterStore>,
+             themes: Arc<Themes>,
+             compressed_body_cache: Arc<CompressedBodyCache>,
+             deduplicator: Arc<Deduplicator>,
+             metrics: Arc<Metrics>,
+             options: QueryParameters,
+             accept_encoding: Option<String>,
+             forwarded_for: Option<String>,
+             user_agent: Option<String>| {
+                let request_id = ulid::Ulid::new();
+                let span = tracing::info_span!("badge_request", %request_id, key = %input);
+
+                async move {
+                let theme = match themes.resolve(options.theme.as_deref()) {
+                    Some(theme) => theme,
+                    None => {
+                        tracing::error!("no theme configured, not even the default");
+                        return Ok(Response::builder()
+                            .status(StatusCode::INTERNAL_SERVER_ERROR)
+                            .body(b"No theme configured".to_vec()))
+                    }
+                };
+
+                let fingerprint = dedup::fingerprint(forwarded_for.as_deref(), user_agent.as_deref());
+                let deduped = match deduplicator.should_dedup(&input, &fingerprint, std::time::Instant::now())
+                {
+                    Ok(deduped) => deduped,
+                    Err(why) => {
+                        tracing::error!(error = %why, "failed to check dedup map, not deduplicating");
+                        false
+                    }
+                };
+                if deduped {
+                    tracing::debug!("deduplicated repeat hit from the same visitor");
                 }
-                Err(why) => Response::builder()
-                    .status(StatusCode::INTERNAL_SERVER_ERROR)
-                    .body(format!(
-                        "Failed to calculate view count, try again: {}",
-                        &why
-                    )),
+
+                let count_result = if deduped {
+                    counter_store.get(&input).await
+                } else {
+                    counter_store.increment(&input).await
+                };
+
+                match count_result {
+                    Ok(view_count) => {
+                        tracing::info!(view_count, deduped, "resolved view count");
+                        let fill_mode = options.fill_mode.unwrap_or(default_fill_mode);
+                        if let Err(why) = metrics.record_request(
+                            &input,
+                            match fill_mode {
+                                FillMode::Random => "random",
+                                FillMode::MIlestone => "milestone",
+                            },
+                        ) {
+                            tracing::error!(error = %why, "failed to record request metrics");
+                        }
+
+                        let color = match fill_mode {
+                            FillMode::MIlestone => {
+                                theme.color_scale.hex_color_for_view_count(view_count)
+                            }
+                            FillMode::Random => theme.color_scale.random_hex_color(),
+                        };
+
+                        let returned_svg_content =
+                            theme.svg.template(&format!("#{}", color), &view_count.to_string());
+
+                        let encoding = accept_encoding
+                            .as_deref()
+                            .and_then(Encoding::negotiate);
+
+                        let response = Response::builder()
+                            .header("Content-Type", "image/svg+xml; charset=utf-8")
+                            .header(
+                                "Cache-Control",
+                                "max-age=0, no-cache, no-store, must-revalidate",
+                            )
+                            .header("Vary", "Accept-Encoding")
+                            .status(StatusCode::OK);
+
+                        Ok::<_, std::convert::Infallible>(match encoding {
+                            Some(encoding) => {
+                                match compressed_body_cache
+                                    .get_or_compress(&returned_svg_content, encoding)
+                                {
+                                    Ok(outcome) => {
+                                        tracing::debug!(
+                                            cache_hit = outcome.was_hit(),
+                                            "compressed body cache outcome"
+                                        );
+                                        response
+                                            .header("Content-Encoding", encoding.header_value())
+                                            .body(outcome.into_bytes())
+                                    }
+                                    Err(why) => {
+                                        tracing::error!(
+                                            error = %why,
+                                            "compressed body cache failed, serving uncompressed"
+                                        );
+                                        response.body(returned_svg_content.into_bytes())
+                                    }
+                                }
+                            }
+                            None => response.body(returned_svg_content.into_bytes()),
+                        })
+                    }
+                    Err(why) => {
+                        tracing::error!(error = %why, "failed to calculate view count");
+                        metrics.record_store_error();
+                        Ok(Response::builder()
+                            .status(StatusCode::INTERNAL_SERVER_ERROR)
+                            .body(
+                                format!("Failed to calculate view count, try again: {}", &why)
+                                    .into_bytes(),
+                            ))
+                    }
+                }
+                }
+                .instrument(span)
             },
         );
 
-    warp::serve(index.or(view_count_route))
-        .run(options.address())
+    let address = config.address();
+    warp::serve(index.or(metrics_route).or(view_count_route))
+        .run(address)
         .await;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> Options {
+        Options {
+            host_on_all_interfaces: false,
+            port: None,
+            max_views: None,
+            database_url: None,
+            config: None,
+            dedup_window: None,
+            forwarded_for_header: None,
+            log_level: None,
+        }
+    }
+
+    #[test]
+    fn resolve_uses_defaults_when_nothing_is_set() {
+        let config = options().resolve(None).unwrap();
+        assert_eq!(config.host, net::IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(config.port, 3030);
+        assert_eq!(config.max_views, 10_400);
+        assert_eq!(config.forwarded_for_header, "x-forwarded-for");
+    }
+
+    #[test]
+    fn resolve_falls_back_to_file_values_when_cli_flags_are_unset() {
+        let file = FileConfig {
+            port: Some(8080),
+            max_views: Some(500),
+            ..Default::default()
+        };
+        let config = options().resolve(Some(file)).unwrap();
+        assert_eq!(config.port, 8080);
+        assert_eq!(config.max_views, 500);
+    }
+
+    #[test]
+    fn resolve_lets_explicit_cli_flags_win_over_the_file() {
+        let mut cli = options();
+        cli.port = Some(9090);
+        let file = FileConfig {
+            port: Some(8080),
+            ..Default::default()
+        };
+        let config = cli.resolve(Some(file)).unwrap();
+        assert_eq!(config.port, 9090);
+    }
+
+    #[test]
+    fn resolve_host_on_all_interfaces_overrides_the_file_host() {
+        let mut cli = options();
+        cli.host_on_all_interfaces = true;
+        let file = FileConfig {
+            host: Some("10.0.0.5".to_string()),
+            ..Default::default()
+        };
+        let config = cli.resolve(Some(file)).unwrap();
+        assert_eq!(config.host, net::IpAddr::from([0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn resolve_fails_on_an_invalid_host_in_the_file() {
+        let file = FileConfig {
+            host: Some("not-an-ip".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            options().resolve(Some(file)),
+            Err(config::Error::InvalidHost(_))
+        ));
+    }
+
+    #[test]
+    fn resolve_lets_cli_forwarded_for_header_win_over_the_default() {
+        let mut cli = options();
+        cli.forwarded_for_header = Some("x-real-ip".to_string());
+        let config = cli.resolve(None).unwrap();
+        assert_eq!(config.forwarded_for_header, "x-real-ip");
+    }
+}
@@ -0,0 +1,118 @@
+//! Prometheus text-format metrics for the badge service.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use std::collections::HashSet;
+
+/// Errors produced while recording or rendering metrics.
+#[derive(Debug)]
+pub enum Error {
+    Lock,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Lock => write!(f, "failed to acquire tracked key set lock"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Counters and gauges tracked for the `/metrics` endpoint.
+#[derive(Default)]
+pub struct Metrics {
+    badge_requests_total: AtomicU64,
+    milestone_fill_requests_total: AtomicU64,
+    random_fill_requests_total: AtomicU64,
+    store_errors_total: AtomicU64,
+    tracked_keys: Mutex<HashSet<String>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn record_request(&self, key: &str, fill_mode: &str) -> Result<(), Error> {
+        self.badge_requests_total.fetch_add(1, Ordering::Relaxed);
+        match fill_mode {
+            "random" => self
+                .random_fill_requests_total
+                .fetch_add(1, Ordering::Relaxed),
+            _ => self
+                .milestone_fill_requests_total
+                .fetch_add(1, Ordering::Relaxed),
+        };
+        self.tracked_keys
+            .lock()
+            .map_err(|_| Error::Lock)?
+            .insert(key.to_string());
+        Ok(())
+    }
+
+    /// Covers both store backend failures and the in-memory store's lock
+    /// being poisoned; both mean a badge request couldn't be counted.
+    pub fn record_store_error(&self) {
+        self.store_errors_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders all metrics in Prometheus text exposition format.
+    pub fn render(&self) -> Result<String, Error> {
+        let distinct_keys = self.tracked_keys.lock().map_err(|_| Error::Lock)?.len();
+
+        Ok(format!(
+            "# HELP badge_requests_total Total number of badge requests served.\n\
+             # TYPE badge_requests_total counter\n\
+             badge_requests_total {}\n\
+             # HELP badge_requests_by_fill_mode_total Badge requests served, partitioned by fill mode.\n\
+             # TYPE badge_requests_by_fill_mode_total counter\n\
+             badge_requests_by_fill_mode_total{{fill_mode=\"milestone\"}} {}\n\
+             badge_requests_by_fill_mode_total{{fill_mode=\"random\"}} {}\n\
+             # HELP badge_store_errors_total Total number of counter store or lock errors.\n\
+             # TYPE badge_store_errors_total counter\n\
+             badge_store_errors_total {}\n\
+             # HELP badge_tracked_keys Number of distinct keys tracked so far.\n\
+             # TYPE badge_tracked_keys gauge\n\
+             badge_tracked_keys {}\n",
+            self.badge_requests_total.load(Ordering::Relaxed),
+            self.milestone_fill_requests_total.load(Ordering::Relaxed),
+            self.random_fill_requests_total.load(Ordering::Relaxed),
+            self.store_errors_total.load(Ordering::Relaxed),
+            distinct_keys,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_reflects_recorded_requests_by_fill_mode() {
+        let metrics = Metrics::new();
+        metrics.record_request("key-a", "milestone").unwrap();
+        metrics.record_request("key-b", "random").unwrap();
+        metrics.record_request("key-a", "random").unwrap();
+
+        let rendered = metrics.render().unwrap();
+
+        assert!(rendered.contains("badge_requests_total 3\n"));
+        assert!(rendered.contains("badge_requests_by_fill_mode_total{fill_mode=\"milestone\"} 1\n"));
+        assert!(rendered.contains("badge_requests_by_fill_mode_total{fill_mode=\"random\"} 2\n"));
+        assert!(rendered.contains("badge_tracked_keys 2\n"));
+    }
+
+    #[test]
+    fn render_reflects_recorded_store_errors() {
+        let metrics = Metrics::new();
+        metrics.record_store_error();
+        metrics.record_store_error();
+
+        let rendered = metrics.render().unwrap();
+
+        assert!(rendered.contains("badge_store_errors_total 2\n"));
+    }
+}
@@ -0,0 +1,198 @@
+//! Pluggable storage backends for view counters.
+//!
+//! The in-memory map that used to live directly in `main` only survives as
+//! long as the process does. [`CounterStore`] lets us swap that out for a
+//! durable backend without touching the warp routes.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use deadpool_postgres::{Config, Pool, Runtime};
+use tokio_postgres::NoTls;
+
+#[derive(Debug)]
+pub enum Error {
+    Lock,
+    CreatePool(deadpool_postgres::CreatePoolError),
+    Pool(deadpool_postgres::PoolError),
+    Postgres(tokio_postgres::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Lock => write!(f, "failed to acquire in-memory store lock"),
+            Error::CreatePool(why) => write!(f, "failed to create connection pool: {}", why),
+            Error::Pool(why) => write!(f, "connection pool error: {}", why),
+            Error::Postgres(why) => write!(f, "postgres error: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<deadpool_postgres::CreatePoolError> for Error {
+    fn from(why: deadpool_postgres::CreatePoolError) -> Self {
+        Error::CreatePool(why)
+    }
+}
+
+impl From<deadpool_postgres::PoolError> for Error {
+    fn from(why: deadpool_postgres::PoolError) -> Self {
+        Error::Pool(why)
+    }
+}
+
+impl From<tokio_postgres::Error> for Error {
+    fn from(why: tokio_postgres::Error) -> Self {
+        Error::Postgres(why)
+    }
+}
+
+/// A backend capable of tracking per-key view counts.
+#[async_trait]
+pub trait CounterStore: Send + Sync {
+    /// Increments `key` by one and returns the new count.
+    async fn increment(&self, key: &str) -> Result<u64, Error>;
+
+    /// Returns the current count for `key` without modifying it.
+    async fn get(&self, key: &str) -> Result<u64, Error>;
+}
+
+/// The original behavior: counts live only as long as the process does.
+#[derive(Debug, Default)]
+pub struct InMemoryStore {
+    counts: Mutex<HashMap<String, u64>>,
+}
+
+impl InMemoryStore {
+    pub fn new() -> Self {
+        InMemoryStore {
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl CounterStore for InMemoryStore {
+    async fn increment(&self, key: &str) -> Result<u64, Error> {
+        let mut gate = self.counts.lock().map_err(|_| Error::Lock)?;
+        let view_count = gate.entry(key.to_string()).or_insert(0);
+        *view_count += 1;
+        Ok(*view_count)
+    }
+
+    async fn get(&self, key: &str) -> Result<u64, Error> {
+        let gate = self.counts.lock().map_err(|_| Error::Lock)?;
+        Ok(gate.get(key).copied().unwrap_or(0))
+    }
+}
+
+/// Postgres-backed store. Counts survive restarts and concurrent increments
+/// stay correct because the increment itself happens inside the database.
+pub struct PostgresStore {
+    pool: Pool,
+}
+
+impl PostgresStore {
+    /// Connects to `database_url`, runs the startup migration, and returns a
+    /// ready-to-use store.
+    pub async fn connect(database_url: &str) -> Result<PostgresStore, Error> {
+        let mut config = Config::new();
+        config.url = Some(database_url.to_string());
+        let pool = config.create_pool(Some(Runtime::Tokio1), NoTls)?;
+
+        let store = PostgresStore { pool };
+        store.migrate().await?;
+        Ok(store)
+    }
+
+    async fn migrate(&self) -> Result<(), Error> {
+        let client = self.pool.get().await?;
+        client
+            .batch_execute(
+                "CREATE TABLE IF NOT EXISTS view_counts (
+                    key TEXT PRIMARY KEY,
+                    count BIGINT NOT NULL DEFAULT 0
+                )",
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CounterStore for PostgresStore {
+    async fn increment(&self, key: &str) -> Result<u64, Error> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_one(
+                "INSERT INTO view_counts (key, count) VALUES ($1, 1)
+                 ON CONFLICT (key) DO UPDATE SET count = view_counts.count + 1
+                 RETURNING count",
+                &[&key],
+            )
+            .await?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    async fn get(&self, key: &str) -> Result<u64, Error> {
+        let client = self.pool.get().await?;
+        let row = client
+            .query_opt("SELECT count FROM view_counts WHERE key = $1", &[&key])
+            .await?;
+        Ok(row.map(|row| row.get::<_, i64>(0) as u64).unwrap_or(0))
+    }
+}
+
+/// Picks the store backend based on `--database-url`: falls back to the
+/// in-memory store when no URL was given.
+pub async fn open(database_url: Option<&str>) -> Result<Arc<dyn CounterStore>, Error> {
+    match database_url {
+        Some(url) => Ok(Arc::new(PostgresStore::connect(url).await?)),
+        None => Ok(Arc::new(InMemoryStore::new())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn increment_starts_a_new_key_at_one() {
+        let store = InMemoryStore::new();
+        assert_eq!(store.increment("key").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn increment_adds_one_on_each_repeat_hit() {
+        let store = InMemoryStore::new();
+        store.increment("key").await.unwrap();
+        store.increment("key").await.unwrap();
+        assert_eq!(store.increment("key").await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn increment_tracks_keys_independently() {
+        let store = InMemoryStore::new();
+        store.increment("key-a").await.unwrap();
+        assert_eq!(store.increment("key-b").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn get_returns_the_count_without_incrementing() {
+        let store = InMemoryStore::new();
+        store.increment("key").await.unwrap();
+        assert_eq!(store.get("key").await.unwrap(), 1);
+        assert_eq!(store.get("key").await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn get_returns_zero_for_an_unseen_key() {
+        let store = InMemoryStore::new();
+        assert_eq!(store.get("key").await.unwrap(), 0);
+    }
+}
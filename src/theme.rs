@@ -0,0 +1,86 @@
+//! A named `(template, color scale)` pair, selectable at request time via
+//! the `theme` query parameter.
+
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{ColorScale, ViewCountSVG};
+
+#[derive(Clone)]
+pub struct Theme {
+    pub svg: Arc<ViewCountSVG>,
+    pub color_scale: Arc<ColorScale>,
+}
+
+/// The full set of themes a server was configured with, keyed by name.
+pub struct Themes {
+    themes: HashMap<String, Theme>,
+}
+
+impl Themes {
+    pub fn new(themes: HashMap<String, Theme>) -> Themes {
+        Themes { themes }
+    }
+
+    /// Looks up `name`, falling back to [`crate::config::DEFAULT_THEME`] if
+    /// it's missing or wasn't requested.
+    pub fn resolve(&self, name: Option<&str>) -> Option<Theme> {
+        name.and_then(|name| self.themes.get(name))
+            .or_else(|| self.themes.get(crate::config::DEFAULT_THEME))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn theme() -> Theme {
+        Theme {
+            svg: Arc::new(ViewCountSVG {
+                before_color_part: String::new(),
+                after_color_before_text_part: String::new(),
+                after_text_part: String::new(),
+            }),
+            color_scale: Arc::new(ColorScale {
+                colors: vec!["#000000".to_string()],
+                max_views: 1,
+            }),
+        }
+    }
+
+    #[test]
+    fn resolve_returns_the_requested_theme_when_it_exists() {
+        let mut themes = HashMap::new();
+        themes.insert(crate::config::DEFAULT_THEME.to_string(), theme());
+        themes.insert("ocean".to_string(), theme());
+        let themes = Themes::new(themes);
+
+        assert!(themes.resolve(Some("ocean")).is_some());
+    }
+
+    #[test]
+    fn resolve_falls_back_to_the_default_theme_when_the_requested_one_is_missing() {
+        let mut themes = HashMap::new();
+        themes.insert(crate::config::DEFAULT_THEME.to_string(), theme());
+        let themes = Themes::new(themes);
+
+        assert!(themes.resolve(Some("nonexistent")).is_some());
+    }
+
+    #[test]
+    fn resolve_uses_the_default_theme_when_none_is_requested() {
+        let mut themes = HashMap::new();
+        themes.insert(crate::config::DEFAULT_THEME.to_string(), theme());
+        let themes = Themes::new(themes);
+
+        assert!(themes.resolve(None).is_some());
+    }
+
+    #[test]
+    fn resolve_returns_none_when_no_themes_are_configured() {
+        let themes = Themes::new(HashMap::new());
+
+        assert!(themes.resolve(None).is_none());
+        assert!(themes.resolve(Some("ocean")).is_none());
+    }
+}